@@ -1,44 +1,95 @@
 // assume only valid bencoding, only one top level object
 mod bencode {
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
     use std::fmt;
     use crate::bencode::Bencode::*;
+
+    #[derive(Debug, PartialEq)]
+    pub enum BencodeError {
+        InputTooShort,
+        UnknownType,
+        InvalidInteger,
+        UnexpectedEnd,
+        TrailingData,
+        Expected(char),
+    }
+
+    impl fmt::Display for BencodeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                BencodeError::InputTooShort => write!(f, "declared length runs past the end of the input"),
+                BencodeError::UnknownType => write!(f, "unrecognized bencode type tag"),
+                BencodeError::InvalidInteger => write!(f, "malformed bencoded integer"),
+                BencodeError::UnexpectedEnd => write!(f, "input ended before a value was terminated"),
+                BencodeError::TrailingData => write!(f, "extra bytes after the top-level value"),
+                BencodeError::Expected(c) => write!(f, "expected '{}'", c),
+            }
+        }
+    }
+
+    impl std::error::Error for BencodeError {}
+
     pub enum Bencode {
         BList(Vec<Bencode>),
-        BString(String),
-        BDict(HashMap<String, Bencode>),
+        // raw bencoded "strings" are arbitrary byte sequences (e.g. the 20-byte
+        // SHA-1 pieces blob), not necessarily valid UTF-8, so we keep them as bytes
+        BBytes(Vec<u8>),
+        // the spec requires dict keys sorted as raw byte strings, so a BTreeMap
+        // over the raw key bytes gives us that ordering for free at encode time
+        BDict(BTreeMap<Vec<u8>, Bencode>),
         BInt(i64),
     }
 
     impl Bencode {
-        fn encode(&self) -> String {
+        fn encode(&self) -> Vec<u8> {
             match self {
-                BString(string) => format!("{}:{}", string.len(), string),
-                BInt(int) => format!("i{}e", int),
+                BBytes(bytes) => {
+                    let mut result = format!("{}:", bytes.len()).into_bytes();
+                    result.extend_from_slice(bytes);
+                    result
+                }
+                BInt(int) => format!("i{}e", int).into_bytes(),
                 BList(list) => _encode_list(list),
                 BDict(dict) => _encode_dict(dict),
             }
         }
 
-        fn decode(string: &str) -> (Bencode, &str) {
-            let mut chars = string.chars();
+        pub(crate) fn decode(bytes: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
+            match bytes.first() {
+                Some(b'l') => _decode_list(&bytes[1..]),
+                Some(b'd') => _decode_dict(&bytes[1..]),
+                Some(b'i') => _decode_int(&bytes[1..]),
+                Some(b'0'..=b'9') => _decode_b_string(bytes),
+                Some(_) => Err(BencodeError::UnknownType),
+                None => Err(BencodeError::UnexpectedEnd),
+            }
+        }
 
-            let starting_char = chars.next();
-            println!(
-                "starting char: {}, full string: {}",
-                starting_char.unwrap().to_string(),
-                chars.as_str()
-            );
+        // decodes a single top-level value and errors if anything is left over
+        pub fn decode_all(bytes: &[u8]) -> Result<Bencode, BencodeError> {
+            let (value, remainder) = Bencode::decode(bytes)?;
+            if remainder.is_empty() {
+                Ok(value)
+            } else {
+                Err(BencodeError::TrailingData)
+            }
+        }
 
-            let result = match starting_char {
-                Some('l') => _decode_list(chars.as_str()),
-                Some('d') => _decode_dict(chars.as_str()),
-                Some('i') => _decode_int(chars.as_str()),
-                None => panic!("unexpected string end"),
-                Some(_) => _decode_b_string(string),
-            };
+        // best-effort view for dict keys and other fields we expect to be
+        // human-readable; never used on fields like `pieces` that are not UTF-8
+        pub fn as_str(&self) -> Option<&str> {
+            match self {
+                BBytes(bytes) => std::str::from_utf8(bytes).ok(),
+                _ => None,
+            }
+        }
 
-            result
+        // convenience lookup for dict fields by a human-readable key
+        pub fn get(&self, key: &str) -> Option<&Bencode> {
+            match self {
+                BDict(dict) => dict.get(key.as_bytes()),
+                _ => None,
+            }
         }
     }
 
@@ -48,7 +99,7 @@ mod bencode {
                 BList(list) => write!(f, "{:?}", list),
                 BDict(dict) => write!(f, "{:?}", dict),
                 BInt(val) => write!(f, "{:?}", val),
-                BString(val) => write!(f, "{:?}", val),
+                BBytes(val) => write!(f, "{:?}", val),
             }
         }
     }
@@ -56,8 +107,8 @@ mod bencode {
     impl PartialEq for Bencode {
         fn eq(&self, other: &Bencode) -> bool {
             match self {
-                Bencode::BString(value) => {
-                    if let Bencode::BString(other_val) = other {
+                Bencode::BBytes(value) => {
+                    if let Bencode::BBytes(other_val) = other {
                         value == other_val
                     } else {
                         false
@@ -88,205 +139,714 @@ mod bencode {
         }
     }
 
-    fn _decode_list(string: &str) -> (Bencode, &str) {
+    fn _decode_list(bytes: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
         let mut results: Vec<Bencode> = Vec::new();
-        let mut substr = string;
-        let mut cursor = string.chars().next();
-        return loop {
-            match cursor {
-                Some('e') => break (BList(results), substr),
-                None => panic!("String terminated unexpectedly"),
+        let mut rest = bytes;
+        loop {
+            match rest.first() {
+                Some(b'e') => break Ok((BList(results), &rest[1..])),
+                None => break Err(BencodeError::UnexpectedEnd),
                 _ => {
-                    let result = Bencode::decode(substr);
-                    results.push(result.0);
-                    substr = result.1;
-                    cursor = substr.chars().next()
+                    let (value, remainder) = Bencode::decode(rest)?;
+                    results.push(value);
+                    rest = remainder;
                 }
             }
-        };
+        }
     }
 
-    fn _decode_dict(string: &str) -> (Bencode, &str) {
+    fn _decode_dict(bytes: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
         // ugh i need to think hard in recursion don't I?
         // decode a string and start slice after string
         // send slice to main decode method, and then take the end cursor
         // if end cursor is 'e', done
-        let mut results: HashMap<String, Bencode> = HashMap::new();
-        let mut chars = string.chars();
-        let mut cursor = Some('0'); // placeholder, so we don't advance the real cursor
-        return loop {
-            match cursor {
-                Some('e') => break (BDict(results), chars.as_str()),
-                None => panic!("String terminated unexpectedly"),
+        let mut results: BTreeMap<Vec<u8>, Bencode> = BTreeMap::new();
+        let mut rest = bytes;
+        loop {
+            match rest.first() {
+                Some(b'e') => break Ok((BDict(results), &rest[1..])),
+                None => break Err(BencodeError::UnexpectedEnd),
                 _ => {
-                    let (key, remainder) = _decode_string(chars.as_str());
-                    let (value, remainder) = Bencode::decode(remainder);
-                    results.insert(String::from(key), value);
-                    chars = remainder.chars();
-                    cursor = chars.next();
+                    let (key, remainder) = _decode_b_string(rest)?;
+                    let key = match key {
+                        BBytes(raw) => raw,
+                        _ => unreachable!(),
+                    };
+                    let (value, remainder) = Bencode::decode(remainder)?;
+                    results.insert(key, value);
+                    rest = remainder;
                 }
             }
-        };
+        }
     }
 
-    fn _decode_int(string: &str) -> (Bencode, &str) {
-        let end = string.find('e').unwrap();
-        let int = string[..end].parse::<i64>().unwrap();
-        return (BInt(int), &string[end + 1..]);
-    }
+    fn _decode_int(bytes: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
+        let end = bytes.iter().position(|&b| b == b'e').ok_or(BencodeError::Expected('e'))?;
+        let digits = &bytes[..end];
 
-    fn _decode_string(string: &str) -> (&str, &str) {
-        let delimiter = string.find(':').unwrap();
-        let length = string[..delimiter].parse::<usize>().unwrap();
-        let word_start = delimiter + 1;
-        let word_end = word_start + length;
+        let negative = digits.first() == Some(&b'-');
+        let magnitude = if negative { &digits[1..] } else { digits };
+        if magnitude.is_empty() || !magnitude.iter().all(u8::is_ascii_digit) {
+            return Err(BencodeError::InvalidInteger);
+        }
+        // the spec forbids leading zeros (i03e) and negative zero (i-0e)
+        if (magnitude.len() > 1 && magnitude[0] == b'0') || (negative && magnitude == b"0") {
+            return Err(BencodeError::InvalidInteger);
+        }
 
-        return (&string[word_start..word_end], &string[word_end..]);
+        let int = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(BencodeError::InvalidInteger)?;
+        Ok((BInt(int), &bytes[end + 1..]))
     }
 
-    fn _decode_b_string(string: &str) -> (Bencode, &str) {
-        let delimiter = string.find(':').unwrap();
-        let length = string[..delimiter].parse::<usize>().unwrap();
+    fn _decode_b_string(bytes: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
+        let delimiter = bytes.iter().position(|&b| b == b':').ok_or(BencodeError::Expected(':'))?;
+        let length = std::str::from_utf8(&bytes[..delimiter])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or(BencodeError::InvalidInteger)?;
         let word_start = delimiter + 1;
-        let word_end = word_start + length;
+        let word_end = word_start
+            .checked_add(length)
+            .ok_or(BencodeError::InputTooShort)?;
+
+        if word_end > bytes.len() {
+            return Err(BencodeError::InputTooShort);
+        }
 
-        return (
-            BString(string[word_start..word_end].to_string()),
-            &string[word_end..],
-        );
+        Ok((
+            BBytes(bytes[word_start..word_end].to_vec()),
+            &bytes[word_end..],
+        ))
     }
 
-    fn _encode_list(list: &Vec<Bencode>) -> String {
-        let mut result = String::from("l");
+    fn _encode_list(list: &Vec<Bencode>) -> Vec<u8> {
+        let mut result = vec![b'l'];
         for obj in list.iter() {
-            result.push_str(&obj.encode())
+            result.extend(obj.encode())
         }
-        result.push_str("e");
+        result.push(b'e');
         result
     }
 
-    fn _encode_dict(dict: &HashMap<String, Bencode>) -> String {
-        let mut result = String::from("d");
+    fn _encode_dict(dict: &BTreeMap<Vec<u8>, Bencode>) -> Vec<u8> {
+        let mut result = vec![b'd'];
+        // BTreeMap iterates keys in ascending byte order, which is exactly the
+        // canonical ordering the spec requires for reproducible info-hashes
         for (key, value) in dict {
-            result.push_str(&format!("{}:{}", key.len(), key));
-            result.push_str(&value.encode());
+            result.extend(format!("{}:", key.len()).into_bytes());
+            result.extend(key);
+            result.extend(value.encode());
         }
-        result.push_str("e");
+        result.push(b'e');
         result
     }
 
     mod tests {
         use crate::bencode::Bencode;
         use crate::bencode::Bencode::*;
-        use std::collections::HashMap;
+        use crate::bencode::BencodeError;
+        use std::collections::BTreeMap;
 
         #[test]
         fn encodes_string() {
             assert_eq!(
-                BString("hamburger".to_string()).encode(),
-                "9:hamburger"
+                BBytes(b"hamburger".to_vec()).encode(),
+                b"9:hamburger"
             );
         }
 
         #[test]
         fn decodes_string() {
             assert_eq!(
-                Bencode::decode("9:hamburger").0,
-                BString("hamburger".to_string())
+                Bencode::decode(b"9:hamburger").unwrap().0,
+                BBytes(b"hamburger".to_vec())
             );
         }
 
         #[test]
         fn encodes_int() {
-            assert_eq!(BInt(10).encode(), "i10e");
+            assert_eq!(BInt(10).encode(), b"i10e");
         }
 
         #[test]
         fn encodes_empty_list() {
-            assert_eq!(BList(Vec::new()).encode(), "le");
+            assert_eq!(BList(Vec::new()).encode(), b"le");
         }
 
         #[test]
         fn encodes_list_of_one() {
-            assert_eq!(BList(vec![BInt(1)]).encode(), "li1ee");
+            assert_eq!(BList(vec![BInt(1)]).encode(), b"li1ee");
         }
 
         #[test]
         fn encodes_list_of_int_and_string() {
             assert_eq!(
-                BList(vec![BInt(1), BString(String::from("ace"))]).encode(),
-                "li1e3:acee"
+                BList(vec![BInt(1), BBytes(b"ace".to_vec())]).encode(),
+                b"li1e3:acee"
             );
         }
 
         #[test]
         fn encodes_list_of_string_and_int() {
             assert_eq!(
-                BList(vec![BString(String::from("ace")), BInt(1)]).encode(),
-                "l3:acei1ee"
+                BList(vec![BBytes(b"ace".to_vec()), BInt(1)]).encode(),
+                b"l3:acei1ee"
             );
         }
 
         #[test]
         fn encodes_empty_dict() {
-            assert_eq!(BDict(HashMap::new()).encode(), "de");
+            assert_eq!(BDict(BTreeMap::new()).encode(), b"de");
         }
 
         #[test]
         fn encodes_dict() {
-            let mut dict = HashMap::new();
-            dict.insert("test".to_string(), BInt(1));
-            assert_eq!(BDict(dict).encode(), "d4:testi1ee");
+            let mut dict = BTreeMap::new();
+            dict.insert(b"test".to_vec(), BInt(1));
+            assert_eq!(BDict(dict).encode(), b"d4:testi1ee");
         }
 
         #[test]
         fn encodes_dict_with_empty_list() {
-            let mut dict = HashMap::new();
-            dict.insert("test".to_string(), BList(vec![]));
-            assert_eq!(BDict(dict).encode(), "d4:testlee");
+            let mut dict = BTreeMap::new();
+            dict.insert(b"test".to_vec(), BList(vec![]));
+            assert_eq!(BDict(dict).encode(), b"d4:testlee");
+        }
+
+        #[test]
+        fn round_trips_dict_keys_in_sorted_order() {
+            let (decoded, _) = Bencode::decode(b"d3:zoo3:bar3:fooi1ee").unwrap();
+            let dict = match &decoded {
+                BDict(dict) => dict,
+                _ => panic!("expected a dict"),
+            };
+            let keys: Vec<&Vec<u8>> = dict.keys().collect();
+            assert_eq!(keys, vec![&b"foo".to_vec(), &b"zoo".to_vec()]);
+            assert_eq!(decoded.encode(), b"d3:fooi1e3:zoo3:bare");
+        }
+
+        #[test]
+        fn decodes_binary_string() {
+            let bytes: Vec<u8> = vec![b'4', b':', 0xff, 0x00, 0xab, 0x01];
+            let (decoded, remainder) = Bencode::decode(&bytes).unwrap();
+            assert_eq!(decoded, BBytes(vec![0xff, 0x00, 0xab, 0x01]));
+            assert!(remainder.is_empty());
+        }
+
+        #[test]
+        fn rejects_truncated_string() {
+            assert_eq!(Bencode::decode(b"9:ham").unwrap_err(), BencodeError::InputTooShort);
+        }
+
+        #[test]
+        fn rejects_unknown_type_tag() {
+            assert_eq!(Bencode::decode(b"x").unwrap_err(), BencodeError::UnknownType);
+        }
+
+        #[test]
+        fn rejects_unterminated_list() {
+            assert_eq!(Bencode::decode(b"li1e").unwrap_err(), BencodeError::UnexpectedEnd);
+        }
+
+        #[test]
+        fn rejects_leading_zero_integer() {
+            assert_eq!(Bencode::decode(b"i03e").unwrap_err(), BencodeError::InvalidInteger);
+        }
+
+        #[test]
+        fn rejects_negative_zero_integer() {
+            assert_eq!(Bencode::decode(b"i-0e").unwrap_err(), BencodeError::InvalidInteger);
+        }
+
+        #[test]
+        fn rejects_trailing_data() {
+            assert_eq!(Bencode::decode_all(b"i1ei2e").unwrap_err(), BencodeError::TrailingData);
         }
     }
 }
 
 mod decode_metainfo {
-    use maplit::hashmap;
-    use crate::bencode::Bencode;
+    use std::fmt;
+    use sha1::{Sha1, Digest};
+    use crate::bencode::{Bencode, BencodeError};
+
+    #[derive(Debug)]
+    pub enum MetainfoError {
+        Bencode(BencodeError),
+        NotADict,
+        MissingField(&'static str),
+        WrongType(&'static str),
+        InvalidPieces,
+    }
+
+    impl fmt::Display for MetainfoError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                MetainfoError::Bencode(e) => write!(f, "malformed bencoding: {}", e),
+                MetainfoError::NotADict => write!(f, "metainfo is not a dict"),
+                MetainfoError::MissingField(name) => write!(f, "missing required field '{}'", name),
+                MetainfoError::WrongType(name) => write!(f, "field '{}' had an unexpected type", name),
+                MetainfoError::InvalidPieces => write!(f, "'pieces' length is not a multiple of 20"),
+            }
+        }
+    }
+
+    impl std::error::Error for MetainfoError {}
+
+    impl From<BencodeError> for MetainfoError {
+        fn from(err: BencodeError) -> Self {
+            MetainfoError::Bencode(err)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct FileEntry {
+        pub path: Vec<String>,
+        pub length: i64,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Mode {
+        SingleFile { length: i64 },
+        MultiFile { files: Vec<FileEntry> },
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct Info {
+        pub name: String,
+        pub piece_length: i64,
+        pub pieces: Vec<[u8; 20]>,
+        pub mode: Mode,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct Metainfo {
+        pub announce: String,
+        pub announce_list: Option<Vec<Vec<String>>>,
+        pub info: Info,
+        pub info_hash: [u8; 20],
+    }
+
+    pub fn decode_metainfo(bytes: &[u8]) -> Result<Metainfo, MetainfoError> {
+        let root = Bencode::decode_all(bytes)?;
+
+        let announce = field_str(&root, "announce")?;
+        let announce_list = match root.get("announce-list") {
+            Some(value) => Some(parse_announce_list(value)?),
+            None => None,
+        };
+
+        let info_value = root.get("info").ok_or(MetainfoError::MissingField("info"))?;
+        let info = parse_info(info_value)?;
+        let info_hash = hash_info(find_info_bytes(bytes)?);
+
+        Ok(Metainfo { announce, announce_list, info, info_hash })
+    }
+
+    fn parse_info(value: &Bencode) -> Result<Info, MetainfoError> {
+        let name = field_str(value, "name")?;
+        let piece_length = field_int(value, "piece length")?;
+        let pieces = match value.get("pieces") {
+            Some(Bencode::BBytes(bytes)) => parse_pieces(bytes)?,
+            _ => return Err(MetainfoError::MissingField("pieces")),
+        };
+
+        let mode = match value.get("length") {
+            Some(Bencode::BInt(length)) => Mode::SingleFile { length: *length },
+            _ => {
+                let files = match value.get("files") {
+                    Some(Bencode::BList(entries)) => entries
+                        .iter()
+                        .map(parse_file_entry)
+                        .collect::<Result<Vec<_>, _>>()?,
+                    _ => return Err(MetainfoError::MissingField("length/files")),
+                };
+                Mode::MultiFile { files }
+            }
+        };
+
+        Ok(Info { name, piece_length, pieces, mode })
+    }
+
+    fn parse_file_entry(value: &Bencode) -> Result<FileEntry, MetainfoError> {
+        let length = field_int(value, "length")?;
+        let path = match value.get("path") {
+            Some(Bencode::BList(parts)) => parts
+                .iter()
+                .map(|part| part.as_str().map(String::from).ok_or(MetainfoError::WrongType("path")))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(MetainfoError::MissingField("path")),
+        };
+        Ok(FileEntry { path, length })
+    }
+
+    fn parse_pieces(bytes: &[u8]) -> Result<Vec<[u8; 20]>, MetainfoError> {
+        if bytes.len() % 20 != 0 {
+            return Err(MetainfoError::InvalidPieces);
+        }
+        Ok(bytes
+            .chunks_exact(20)
+            .map(|chunk| {
+                let mut sha = [0u8; 20];
+                sha.copy_from_slice(chunk);
+                sha
+            })
+            .collect())
+    }
+
+    fn parse_announce_list(value: &Bencode) -> Result<Vec<Vec<String>>, MetainfoError> {
+        match value {
+            Bencode::BList(tiers) => tiers
+                .iter()
+                .map(|tier| match tier {
+                    Bencode::BList(urls) => urls
+                        .iter()
+                        .map(|url| url.as_str().map(String::from).ok_or(MetainfoError::WrongType("announce-list")))
+                        .collect::<Result<Vec<_>, _>>(),
+                    _ => Err(MetainfoError::WrongType("announce-list")),
+                })
+                .collect(),
+            _ => Err(MetainfoError::WrongType("announce-list")),
+        }
+    }
+
+    fn field_str(value: &Bencode, name: &'static str) -> Result<String, MetainfoError> {
+        value
+            .get(name)
+            .and_then(Bencode::as_str)
+            .map(String::from)
+            .ok_or(MetainfoError::MissingField(name))
+    }
+
+    fn field_int(value: &Bencode, name: &'static str) -> Result<i64, MetainfoError> {
+        match value.get(name) {
+            Some(Bencode::BInt(n)) => Ok(*n),
+            Some(_) => Err(MetainfoError::WrongType(name)),
+            None => Err(MetainfoError::MissingField(name)),
+        }
+    }
+
+    // re-encoding the decoded `info` dict is not guaranteed to reproduce the
+    // original bytes (non-canonical key order, alternate int encodings, etc), so
+    // we walk the raw input ourselves to find exactly the bytes that hashed to
+    // the info_hash peers expect, rather than recomputing them from the Bencode tree
+    fn find_info_bytes(bytes: &[u8]) -> Result<&[u8], MetainfoError> {
+        let mut rest = bytes.strip_prefix(b"d").ok_or(MetainfoError::NotADict)?;
+        loop {
+            match rest.first() {
+                Some(b'e') => return Err(MetainfoError::MissingField("info")),
+                None => return Err(BencodeError::UnexpectedEnd.into()),
+                _ => {
+                    let (key, after_key) = Bencode::decode(rest)?;
+                    let value_start = after_key;
+                    let (_, after_value) = Bencode::decode(value_start)?;
+                    let consumed = value_start.len() - after_value.len();
 
-    fn decode_metainfo() -> Bencode {
-        // TODO actual implementation
-        return Bencode::BDict(hashmap!{});
+                    if matches!(&key, Bencode::BBytes(raw) if raw == b"info") {
+                        return Ok(&value_start[..consumed]);
+                    }
+                    rest = after_value;
+                }
+            }
+        }
+    }
+
+    fn hash_info(info_bytes: &[u8]) -> [u8; 20] {
+        let mut hasher = Sha1::new();
+        hasher.update(info_bytes);
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&hasher.digest().bytes());
+        info_hash
+    }
+
+    mod tests {
+        use crate::decode_metainfo::{decode_metainfo, FileEntry, MetainfoError, Mode};
+
+        #[test]
+        fn computes_the_known_answer_info_hash() {
+            let meta = b"d8:announce20:http://tracker.test/4:infod6:lengthi12e4:name8:test.txt12:piece lengthi4e6:pieces40:\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22ee";
+            let metainfo = decode_metainfo(meta).unwrap();
+            assert_eq!(
+                metainfo.info_hash,
+                [
+                    0x40, 0x67, 0x7c, 0x4a, 0x78, 0x47, 0x6d, 0x85, 0x6d, 0xa1, 0xbf, 0x83, 0xfb,
+                    0x29, 0xfd, 0x8c, 0x0f, 0x3a, 0x8f, 0x77,
+                ]
+            );
+        }
+
+        #[test]
+        fn parses_single_file_mode() {
+            let meta = b"d8:announce20:http://tracker.test/4:infod6:lengthi12e4:name8:test.txt12:piece lengthi4e6:pieces40:\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x11\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22\x22ee";
+            let metainfo = decode_metainfo(meta).unwrap();
+            assert_eq!(metainfo.announce, "http://tracker.test/");
+            assert_eq!(metainfo.info.name, "test.txt");
+            assert_eq!(metainfo.info.piece_length, 4);
+            assert_eq!(metainfo.info.pieces.len(), 2);
+            assert_eq!(metainfo.info.mode, Mode::SingleFile { length: 12 });
+        }
+
+        #[test]
+        fn parses_multi_file_mode() {
+            let meta = b"d8:announce20:http://tracker.test/4:infod5:filesld6:lengthi5e4:pathl1:a1:beed6:lengthi7e4:pathl1:ceee4:name6:bundle12:piece lengthi4e6:pieces20:33333333333333333333ee";
+            let metainfo = decode_metainfo(meta).unwrap();
+            assert_eq!(metainfo.info.name, "bundle");
+            assert_eq!(
+                metainfo.info.mode,
+                Mode::MultiFile {
+                    files: vec![
+                        FileEntry { path: vec!["a".into(), "b".into()], length: 5 },
+                        FileEntry { path: vec!["c".into()], length: 7 },
+                    ],
+                }
+            );
+        }
+
+        #[test]
+        fn rejects_a_pieces_length_that_is_not_a_multiple_of_twenty() {
+            let meta = b"d8:announce20:http://tracker.test/4:infod6:lengthi12e4:name8:test.txt12:piece lengthi4e6:pieces5:aaaaaee";
+            assert!(matches!(decode_metainfo(meta), Err(MetainfoError::InvalidPieces)));
+        }
     }
 }
 
 mod tracker_communication {
-    struct Request {
-        info_hash: String, // TODO is there a better SHA 1 byte string representation?
-        peer_id: String,
-        port: usize,
-        uploaded: String,
-        downloaded: String,
-        left: String,
-        compact: bool, // true should become 1, false should become 0
-        no_peer_id: Option<bool>,
-        event: Option<TorrentEvent>,
-        ip: Option<String>,
-        numwant: Option<usize>,
-        key: Option<String>,
-        trackerid: Option<String>
-    }
-
-    enum TorrentEvent {
+    use std::fmt;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use crate::bencode::{Bencode, BencodeError};
+
+    pub struct Request {
+        pub info_hash: [u8; 20],
+        pub peer_id: [u8; 20],
+        pub port: usize,
+        pub uploaded: u64,
+        pub downloaded: u64,
+        pub left: u64,
+        pub compact: bool, // true should become 1, false should become 0
+        pub no_peer_id: Option<bool>,
+        pub event: Option<TorrentEvent>,
+        pub ip: Option<String>,
+        pub numwant: Option<usize>,
+        pub key: Option<String>,
+        pub trackerid: Option<String>
+    }
+
+    pub enum TorrentEvent {
         Started,
         Stopped,
         Completed
     }
 
-    // TODO response
+    impl TorrentEvent {
+        fn as_str(&self) -> &'static str {
+            match self {
+                TorrentEvent::Started => "started",
+                TorrentEvent::Stopped => "stopped",
+                TorrentEvent::Completed => "completed",
+            }
+        }
+    }
+
+    impl Request {
+        pub fn to_query_string(&self) -> String {
+            let mut params = vec![
+                format!("info_hash={}", percent_encode(&self.info_hash)),
+                format!("peer_id={}", percent_encode(&self.peer_id)),
+                format!("port={}", self.port),
+                format!("uploaded={}", self.uploaded),
+                format!("downloaded={}", self.downloaded),
+                format!("left={}", self.left),
+                format!("compact={}", if self.compact { 1 } else { 0 }),
+            ];
+
+            if let Some(no_peer_id) = self.no_peer_id {
+                params.push(format!("no_peer_id={}", if no_peer_id { 1 } else { 0 }));
+            }
+            if let Some(event) = &self.event {
+                params.push(format!("event={}", event.as_str()));
+            }
+            if let Some(ip) = &self.ip {
+                params.push(format!("ip={}", percent_encode(ip.as_bytes())));
+            }
+            if let Some(numwant) = self.numwant {
+                params.push(format!("numwant={}", numwant));
+            }
+            if let Some(key) = &self.key {
+                params.push(format!("key={}", percent_encode(key.as_bytes())));
+            }
+            if let Some(trackerid) = &self.trackerid {
+                params.push(format!("trackerid={}", percent_encode(trackerid.as_bytes())));
+            }
+
+            params.join("&")
+        }
+
+        pub fn to_announce_url(&self, announce: &str) -> String {
+            format!("{}?{}", announce, self.to_query_string())
+        }
+    }
+
+    // percent-encode raw bytes per RFC 3986's unreserved set; info_hash and
+    // peer_id are arbitrary bytes, not necessarily valid UTF-8, so this must
+    // work byte-by-byte rather than through a string-oriented encoder
+    fn percent_encode(bytes: &[u8]) -> String {
+        let mut result = String::with_capacity(bytes.len() * 3);
+        for &byte in bytes {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    result.push(byte as char)
+                }
+                _ => result.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        result
+    }
+
+    #[derive(Debug)]
+    pub enum TrackerError {
+        Bencode(BencodeError),
+        WrongType(&'static str),
+        InvalidCompactPeers,
+    }
+
+    impl fmt::Display for TrackerError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TrackerError::Bencode(e) => write!(f, "malformed bencoding: {}", e),
+                TrackerError::WrongType(name) => write!(f, "field '{}' had an unexpected type", name),
+                TrackerError::InvalidCompactPeers => write!(f, "compact peers length is not a multiple of 6"),
+            }
+        }
+    }
+
+    impl std::error::Error for TrackerError {}
+
+    impl From<BencodeError> for TrackerError {
+        fn from(err: BencodeError) -> Self {
+            TrackerError::Bencode(err)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct Response {
+        pub interval: Option<i64>,
+        pub failure_reason: Option<String>,
+        pub peers: Vec<SocketAddrV4>,
+    }
+
+    pub fn parse_response(bytes: &[u8]) -> Result<Response, TrackerError> {
+        let root = Bencode::decode_all(bytes)?;
+
+        let failure_reason = root.get("failure reason").and_then(Bencode::as_str).map(String::from);
+        let interval = match root.get("interval") {
+            Some(Bencode::BInt(n)) => Some(*n),
+            _ => None,
+        };
+        let peers = match root.get("peers") {
+            Some(Bencode::BBytes(raw)) => parse_compact_peers(raw)?,
+            Some(Bencode::BList(entries)) => parse_dict_peers(entries)?,
+            _ => Vec::new(),
+        };
+
+        Ok(Response { interval, failure_reason, peers })
+    }
+
+    // the compact model packs each peer into 4 bytes of big-endian IPv4 plus
+    // 2 bytes of big-endian port, back to back with no delimiters
+    fn parse_compact_peers(bytes: &[u8]) -> Result<Vec<SocketAddrV4>, TrackerError> {
+        if bytes.len() % 6 != 0 {
+            return Err(TrackerError::InvalidCompactPeers);
+        }
+        Ok(bytes
+            .chunks_exact(6)
+            .map(|chunk| {
+                let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                SocketAddrV4::new(ip, port)
+            })
+            .collect())
+    }
+
+    fn parse_dict_peers(entries: &[Bencode]) -> Result<Vec<SocketAddrV4>, TrackerError> {
+        entries
+            .iter()
+            .map(|entry| {
+                let ip: Ipv4Addr = entry
+                    .get("ip")
+                    .and_then(Bencode::as_str)
+                    .ok_or(TrackerError::WrongType("ip"))?
+                    .parse()
+                    .map_err(|_| TrackerError::WrongType("ip"))?;
+                let port = match entry.get("port") {
+                    Some(Bencode::BInt(n)) => u16::try_from(*n).map_err(|_| TrackerError::WrongType("port"))?,
+                    _ => return Err(TrackerError::WrongType("port")),
+                };
+                Ok(SocketAddrV4::new(ip, port))
+            })
+            .collect()
+    }
 
     // TODO scrape
+
+    mod tests {
+        use crate::tracker_communication::*;
+
+        #[test]
+        fn query_string_encodes_raw_bytes_and_fields() {
+            let request = Request {
+                info_hash: [0xff; 20],
+                peer_id: *b"-SC0001-123456789012",
+                port: 6881,
+                uploaded: 0,
+                downloaded: 0,
+                left: 1000,
+                compact: true,
+                no_peer_id: None,
+                event: Some(TorrentEvent::Started),
+                ip: None,
+                numwant: None,
+                key: None,
+                trackerid: None,
+            };
+
+            let query = request.to_query_string();
+            assert!(query.contains("info_hash=%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF%FF"));
+            assert!(query.contains("peer_id=-SC0001-123456789012"));
+            assert!(query.contains("port=6881"));
+            assert!(query.contains("compact=1"));
+            assert!(query.contains("event=started"));
+        }
+
+        #[test]
+        fn parses_compact_peers() {
+            let bencoded = b"d8:intervali900e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x08\x08\x08\x08\x1a\xe1e";
+            let response = parse_response(bencoded).unwrap();
+            assert_eq!(response.interval, Some(900));
+            assert_eq!(response.peers.len(), 2);
+            assert_eq!(response.peers[0].port(), 6881);
+        }
+
+        #[test]
+        fn surfaces_failure_reason() {
+            let bencoded = b"d14:failure reason11:bad requeste";
+            let response = parse_response(bencoded).unwrap();
+            assert_eq!(response.failure_reason, Some("bad request".to_string()));
+        }
+
+        #[test]
+        fn rejects_a_dict_peer_port_out_of_u16_range() {
+            let bencoded = b"d5:peersld2:ip9:127.0.0.14:porti70000eeee";
+            assert!(matches!(parse_response(bencoded), Err(TrackerError::WrongType("port"))));
+        }
+    }
 }
 
 mod peer_protocol {
+    use std::fmt;
+    use std::convert::TryInto;
 
     struct PeerState {
         am_choking: bool,
@@ -304,50 +864,382 @@ mod peer_protocol {
 
     // nb: integers are 4 byte big endian values
 
-    struct Handshake {
-        pstrlen: u8,
-        pstr: String,
-        reserved: u64,
-        info_hash: String, // TODO is this the correct hash encoding?
-        peer_id: String
+    #[derive(Debug, PartialEq)]
+    pub enum PeerError {
+        UnknownMessageId(u8),
+        InvalidLength { expected: u32, actual: u32 },
+    }
+
+    impl fmt::Display for PeerError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                PeerError::UnknownMessageId(id) => write!(f, "unknown message id {}", id),
+                PeerError::InvalidLength { expected, actual } => {
+                    write!(f, "expected a payload of {} bytes, got {}", expected, actual)
+                }
+            }
+        }
     }
 
-    enum Messages {
+    impl std::error::Error for PeerError {}
+
+    #[derive(Debug)]
+    pub struct Handshake {
+        pub pstrlen: u8,
+        pub pstr: String,
+        pub reserved: u64,
+        pub info_hash: [u8; 20],
+        pub peer_id: [u8; 20]
+    }
+
+    impl Handshake {
+        pub fn encode(&self) -> Vec<u8> {
+            let mut result = Vec::with_capacity(1 + self.pstr.len() + 8 + 20 + 20);
+            result.push(self.pstrlen);
+            result.extend(self.pstr.as_bytes());
+            result.extend(self.reserved.to_be_bytes());
+            result.extend(&self.info_hash);
+            result.extend(&self.peer_id);
+            result
+        }
+
+        // streaming decode: returns Ok((None, 0)) when `buf` doesn't yet hold a
+        // full handshake, so callers can drive it off an incremental read loop
+        pub fn decode(buf: &[u8]) -> Result<(Option<Handshake>, usize), PeerError> {
+            let pstrlen = match buf.first() {
+                Some(&len) => len as usize,
+                None => return Ok((None, 0)),
+            };
+            let total = 1 + pstrlen + 8 + 20 + 20;
+            if buf.len() < total {
+                return Ok((None, 0));
+            }
+
+            let pstr = String::from_utf8_lossy(&buf[1..1 + pstrlen]).into_owned();
+            let mut offset = 1 + pstrlen;
+            let reserved = u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap());
+            offset += 8;
+            let mut info_hash = [0u8; 20];
+            info_hash.copy_from_slice(&buf[offset..offset + 20]);
+            offset += 20;
+            let mut peer_id = [0u8; 20];
+            peer_id.copy_from_slice(&buf[offset..offset + 20]);
+            offset += 20;
+
+            Ok((Some(Handshake { pstrlen: buf[0], pstr, reserved, info_hash, peer_id }), offset))
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum Messages {
         KeepAlive,
         Choke,
         Unchoke,
         Interested,
         NotInterested,
         Have(Have),
-        Bitfield(Raw_Bitfield),
+        Bitfield(RawBitfield),
         Request(Request),
         Piece(Piece),
         Cancel(Cancel),
-        Port(String)
+        Port(u16)
     }
 
-    struct Have { piece_index: u32 }
+    #[derive(Debug)]
+    pub struct Have { pub piece_index: u32 }
     // TODO should this carry the bitfield object we made maybe?
-    struct Raw_Bitfield { bytes: Vec<u8> }
-    struct Request { index: u32, begin: u32, length: u32 }
-    struct Piece { index: u32, begin: u32, block: Vec<u8> }
-    struct Cancel { index: u32, begin: u32, length: u32 }
+    #[derive(Debug)]
+    pub struct RawBitfield { pub bytes: Vec<u8> }
+    #[derive(Debug)]
+    pub struct Request { pub index: u32, pub begin: u32, pub length: u32 }
+    #[derive(Debug)]
+    pub struct Piece { pub index: u32, pub begin: u32, pub block: Vec<u8> }
+    #[derive(Debug)]
+    pub struct Cancel { pub index: u32, pub begin: u32, pub length: u32 }
+
+    impl Messages {
+        pub fn encode(&self) -> Vec<u8> {
+            match self {
+                Messages::KeepAlive => 0u32.to_be_bytes().to_vec(),
+                Messages::Choke => frame(0, &[]),
+                Messages::Unchoke => frame(1, &[]),
+                Messages::Interested => frame(2, &[]),
+                Messages::NotInterested => frame(3, &[]),
+                Messages::Have(have) => frame(4, &have.piece_index.to_be_bytes()),
+                Messages::Bitfield(bitfield) => frame(5, &bitfield.bytes),
+                Messages::Request(request) => frame(6, &request_payload(request.index, request.begin, request.length)),
+                Messages::Piece(piece) => {
+                    let mut payload = Vec::with_capacity(8 + piece.block.len());
+                    payload.extend(piece.index.to_be_bytes());
+                    payload.extend(piece.begin.to_be_bytes());
+                    payload.extend(&piece.block);
+                    frame(7, &payload)
+                }
+                Messages::Cancel(cancel) => frame(8, &request_payload(cancel.index, cancel.begin, cancel.length)),
+                Messages::Port(port) => frame(9, &port.to_be_bytes()),
+            }
+        }
+
+        // streaming decode: returns Ok((None, 0)) when `buf` doesn't yet hold a
+        // complete message, so callers can drive it off an incremental read loop
+        pub fn decode(buf: &[u8]) -> Result<(Option<Messages>, usize), PeerError> {
+            if buf.len() < 4 {
+                return Ok((None, 0));
+            }
+            let length = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+            if length == 0 {
+                return Ok((Some(Messages::KeepAlive), 4));
+            }
+            if buf.len() < 4 + length {
+                return Ok((None, 0));
+            }
+
+            let id = buf[4];
+            let payload = &buf[5..4 + length];
+            let consumed = 4 + length;
+
+            let message = match id {
+                0 => Messages::Choke,
+                1 => Messages::Unchoke,
+                2 => Messages::Interested,
+                3 => Messages::NotInterested,
+                4 => {
+                    expect_len(payload, 4)?;
+                    Messages::Have(Have { piece_index: read_u32(payload, 0) })
+                }
+                5 => Messages::Bitfield(RawBitfield { bytes: payload.to_vec() }),
+                6 => {
+                    expect_len(payload, 12)?;
+                    Messages::Request(Request {
+                        index: read_u32(payload, 0),
+                        begin: read_u32(payload, 4),
+                        length: read_u32(payload, 8),
+                    })
+                }
+                7 => {
+                    if payload.len() < 8 {
+                        return Err(PeerError::InvalidLength { expected: 8, actual: payload.len() as u32 });
+                    }
+                    Messages::Piece(Piece {
+                        index: read_u32(payload, 0),
+                        begin: read_u32(payload, 4),
+                        block: payload[8..].to_vec(),
+                    })
+                }
+                8 => {
+                    expect_len(payload, 12)?;
+                    Messages::Cancel(Cancel {
+                        index: read_u32(payload, 0),
+                        begin: read_u32(payload, 4),
+                        length: read_u32(payload, 8),
+                    })
+                }
+                9 => {
+                    expect_len(payload, 2)?;
+                    Messages::Port(u16::from_be_bytes([payload[0], payload[1]]))
+                }
+                unknown => return Err(PeerError::UnknownMessageId(unknown)),
+            };
+
+            Ok((Some(message), consumed))
+        }
+    }
+
+    fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+        let length = (1 + payload.len()) as u32;
+        let mut result = Vec::with_capacity(4 + 1 + payload.len());
+        result.extend(length.to_be_bytes());
+        result.push(id);
+        result.extend(payload);
+        result
+    }
+
+    fn request_payload(index: u32, begin: u32, length: u32) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(12);
+        payload.extend(index.to_be_bytes());
+        payload.extend(begin.to_be_bytes());
+        payload.extend(length.to_be_bytes());
+        payload
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn expect_len(payload: &[u8], expected: usize) -> Result<(), PeerError> {
+        if payload.len() == expected {
+            Ok(())
+        } else {
+            Err(PeerError::InvalidLength { expected: expected as u32, actual: payload.len() as u32 })
+        }
+    }
+
+    mod tests {
+        use crate::peer_protocol::*;
+
+        #[test]
+        fn round_trips_keep_alive() {
+            let encoded = Messages::KeepAlive.encode();
+            assert_eq!(encoded, vec![0, 0, 0, 0]);
+            let (message, consumed) = Messages::decode(&encoded).unwrap();
+            assert!(matches!(message, Some(Messages::KeepAlive)));
+            assert_eq!(consumed, 4);
+        }
+
+        #[test]
+        fn round_trips_have() {
+            let encoded = Messages::Have(Have { piece_index: 7 }).encode();
+            let (message, consumed) = Messages::decode(&encoded).unwrap();
+            assert_eq!(consumed, encoded.len());
+            match message {
+                Some(Messages::Have(have)) => assert_eq!(have.piece_index, 7),
+                _ => panic!("expected Have"),
+            }
+        }
+
+        #[test]
+        fn round_trips_piece() {
+            let piece = Piece { index: 1, begin: 2, block: vec![9, 9, 9] };
+            let encoded = Messages::Piece(piece).encode();
+            let (message, consumed) = Messages::decode(&encoded).unwrap();
+            assert_eq!(consumed, encoded.len());
+            match message {
+                Some(Messages::Piece(piece)) => {
+                    assert_eq!(piece.index, 1);
+                    assert_eq!(piece.begin, 2);
+                    assert_eq!(piece.block, vec![9, 9, 9]);
+                }
+                _ => panic!("expected Piece"),
+            }
+        }
+
+        #[test]
+        fn decode_waits_for_more_bytes_on_partial_buffer() {
+            let encoded = Messages::Request(Request { index: 0, begin: 0, length: 1 }).encode();
+            let (message, consumed) = Messages::decode(&encoded[..encoded.len() - 1]).unwrap();
+            assert!(message.is_none());
+            assert_eq!(consumed, 0);
+        }
+
+        #[test]
+        fn rejects_malformed_request_payload() {
+            // length prefix says a 5-byte payload (1 id + 4), but request needs 12
+            let bytes = vec![0, 0, 0, 5, 6, 0, 0, 0, 0];
+            assert_eq!(
+                Messages::decode(&bytes).unwrap_err(),
+                PeerError::InvalidLength { expected: 12, actual: 4 }
+            );
+        }
+
+        #[test]
+        fn rejects_unknown_message_id() {
+            let bytes = vec![0, 0, 0, 1, 200];
+            assert_eq!(Messages::decode(&bytes).unwrap_err(), PeerError::UnknownMessageId(200));
+        }
+
+        #[test]
+        fn round_trips_handshake() {
+            let handshake = Handshake {
+                pstrlen: 19,
+                pstr: "BitTorrent protocol".to_string(),
+                reserved: 0,
+                info_hash: [1u8; 20],
+                peer_id: [2u8; 20],
+            };
+            let encoded = handshake.encode();
+            let (decoded, consumed) = Handshake::decode(&encoded).unwrap();
+            assert_eq!(consumed, encoded.len());
+            let decoded = decoded.unwrap();
+            assert_eq!(decoded.pstr, "BitTorrent protocol");
+            assert_eq!(decoded.info_hash, [1u8; 20]);
+            assert_eq!(decoded.peer_id, [2u8; 20]);
+        }
+    }
 }
 
 mod pieces {
+    use std::cell::Cell;
     use crate::bitfield::Bitfield;
     use sha1::{Sha1, Digest};
 
-    struct Pieces {
+    // below this many completed pieces we pick randomly among rarest-or-not
+    // candidates rather than strictly by rarity, so fresh peers don't all pile
+    // onto the very first piece the swarm happens to agree is rarest
+    const RANDOM_FIRST_PIECE_COUNT: usize = 4;
+
+    pub struct Pieces {
         bitfield: Bitfield,
-        pieces: Vec<Piece>
+        pieces: Vec<Piece>,
+        // how many connected peers have advertised each piece index, fed by
+        // incoming Bitfield/Have messages
+        peer_counts: Vec<u32>,
+        rng_state: Cell<u64>,
     }
 
     impl Pieces {
+        pub fn new(bitfield: Bitfield, pieces: Vec<Piece>) -> Pieces {
+            let peer_counts = vec![0; pieces.len()];
+            Pieces { bitfield, pieces, peer_counts, rng_state: Cell::new(0x2545_f491_4f6c_dd1d) }
+        }
 
+        pub fn record_peer_bitfield(&mut self, peer_has: &Bitfield) {
+            // a peer's advertised bitfield may be shorter than our piece count
+            // (malformed wire data, or a stale peer count); never index past it
+            let usable_len = self.peer_counts.len().min(peer_has.len());
+            for index in 0..usable_len {
+                if peer_has.get_value(index) {
+                    self.peer_counts[index] += 1;
+                }
+            }
+        }
+
+        pub fn record_have(&mut self, piece_index: u32) {
+            if let Some(count) = self.peer_counts.get_mut(piece_index as usize) {
+                *count += 1;
+            }
+        }
+
+        // rarest-first piece selection: among the pieces we're missing that the
+        // given peer has, prefer whichever is advertised by the fewest peers. For
+        // the first few pieces, pick uniformly at random among candidates instead.
+        pub fn next_piece(&self, peer_has: &Bitfield) -> Option<u32> {
+            // a peer's advertised bitfield may be shorter than our piece count
+            // (malformed wire data, or a stale peer count); never index past it
+            let peer_len = peer_has.len();
+            let candidates: Vec<u32> = self
+                .bitfield
+                .iter_missing()
+                .filter(|&index| index < peer_len && peer_has.get_value(index))
+                .map(|index| index as u32)
+                .collect();
+
+            if candidates.is_empty() {
+                return None;
+            }
+
+            if self.bitfield.count_set() < RANDOM_FIRST_PIECE_COUNT {
+                let pick = self.next_random(candidates.len());
+                return Some(candidates[pick]);
+            }
+
+            candidates
+                .into_iter()
+                .min_by_key(|&index| self.peer_counts[index as usize])
+        }
+
+        // small xorshift PRNG so random-first selection doesn't need an external
+        // dependency; not cryptographic, just enough to spread out first picks
+        fn next_random(&self, bound: usize) -> usize {
+            let mut state = self.rng_state.get();
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            self.rng_state.set(state);
+            (state as usize) % bound
+        }
     }
 
-    struct Piece {
+    pub(crate) struct Piece {
         sha: [u8; 20],
         index: u32,
         bytes: Vec<u8>,
@@ -358,13 +1250,91 @@ mod pieces {
         fn is_done(&mut self) -> bool {
             if !self._done {
                 let mut hasher = Sha1::new();
-                hasher.input(&self.bytes);
-                let result = hasher.result();
+                hasher.update(&self.bytes);
+                let result = hasher.digest().bytes();
                 self._done = result[..] == self.sha;
             }
             self._done
         }
     }
+
+    mod tests {
+        use crate::bitfield::Bitfield;
+        use crate::pieces::{Piece, Pieces};
+
+        fn blank_piece(index: u32) -> Piece {
+            Piece { sha: [0; 20], index, bytes: Vec::new(), _done: false }
+        }
+
+        fn pieces_of(count: usize) -> Pieces {
+            let bitfield = Bitfield::create(count);
+            let pieces = (0..count as u32).map(blank_piece).collect();
+            Pieces::new(bitfield, pieces)
+        }
+
+        fn all_has(count: usize) -> Bitfield {
+            let mut bitfield = Bitfield::create(count);
+            for index in 0..count {
+                bitfield.set_value(index);
+            }
+            bitfield
+        }
+
+        #[test]
+        fn picks_the_rarest_piece_once_past_the_random_first_window() {
+            let mut pieces = pieces_of(6);
+            // mark enough pieces complete locally to leave the random-first window
+            for index in 0..4 {
+                pieces.bitfield.set_value(index);
+            }
+            // piece 4 advertised by 3 peers, piece 5 by only 1
+            for _ in 0..3 {
+                let mut has = Bitfield::create(6);
+                has.set_value(4);
+                pieces.record_peer_bitfield(&has);
+            }
+            let mut rare_peer = Bitfield::create(6);
+            rare_peer.set_value(5);
+            pieces.record_peer_bitfield(&rare_peer);
+
+            let peer_has = all_has(6);
+            assert_eq!(pieces.next_piece(&peer_has), Some(5));
+        }
+
+        #[test]
+        fn only_considers_pieces_the_peer_actually_has() {
+            let mut pieces = pieces_of(3);
+            for index in 0..3 {
+                pieces.bitfield.set_value(index);
+            }
+            // leave index 0 incomplete again so there is something to pick
+            pieces.bitfield.unset_value(0);
+
+            let mut peer_has = Bitfield::create(3);
+            peer_has.set_value(1); // peer doesn't have piece 0
+
+            assert_eq!(pieces.next_piece(&peer_has), None);
+        }
+
+        #[test]
+        fn returns_none_when_nothing_is_missing() {
+            let mut pieces = pieces_of(2);
+            pieces.bitfield.set_value(0);
+            pieces.bitfield.set_value(1);
+
+            let peer_has = all_has(2);
+            assert_eq!(pieces.next_piece(&peer_has), None);
+        }
+
+        #[test]
+        fn tolerates_a_peer_bitfield_shorter_than_our_piece_count() {
+            let mut pieces = pieces_of(20);
+            let short_peer_has = Bitfield::create(3);
+
+            pieces.record_peer_bitfield(&short_peer_has);
+            assert_eq!(pieces.next_piece(&short_peer_has), None);
+        }
+    }
 }
 
 mod bitfield {
@@ -375,12 +1345,12 @@ mod bitfield {
 
     impl Bitfield {
 
-        fn create(bit_size: usize) -> Bitfield {
+        pub fn create(bit_size: usize) -> Bitfield {
             let byte_size = (bit_size/8) + (if bit_size % 8 != 0 {1} else {0});
             return Bitfield { len: bit_size, bytes: vec![0; byte_size] };
         }
 
-        fn set_value(&mut self, index: usize) {
+        pub fn set_value(&mut self, index: usize) {
             let byte_index = index / 8;
             let bit_index = index % 8;
 
@@ -389,7 +1359,7 @@ mod bitfield {
             self.bytes[byte_index] = self.bytes[byte_index] | bit_twiddle;
         }
 
-        fn unset_value(&mut self, index: usize) {
+        pub fn unset_value(&mut self, index: usize) {
             let byte_index = index / 8;
             let bit_index = index % 8;
 
@@ -398,7 +1368,7 @@ mod bitfield {
             self.bytes[byte_index] = self.bytes[byte_index] & bit_twiddle;
         }
 
-        fn get_value(&self, index: usize) -> bool {
+        pub fn get_value(&self, index: usize) -> bool {
             let byte_index = index / 8;
             let bit_index = index % 8;
 
@@ -406,6 +1376,18 @@ mod bitfield {
 
             return (self.bytes[byte_index] & mask) != 0;
         }
+
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        pub fn count_set(&self) -> usize {
+            (0..self.len).filter(|&index| self.get_value(index)).count()
+        }
+
+        pub fn iter_missing(&self) -> impl Iterator<Item = usize> + '_ {
+            (0..self.len).filter(move |&index| !self.get_value(index))
+        }
     }
 
     mod tests {
@@ -447,5 +1429,23 @@ mod bitfield {
             vals.unset_value(63);
             assert_eq!(vals.get_value(63), false);
         }
+
+        #[test]
+        fn counts_set_bits() {
+            let mut vals = Bitfield::create(10);
+            assert_eq!(vals.count_set(), 0);
+            vals.set_value(2);
+            vals.set_value(9);
+            assert_eq!(vals.count_set(), 2);
+        }
+
+        #[test]
+        fn iterates_missing_indices() {
+            let mut vals = Bitfield::create(5);
+            vals.set_value(1);
+            vals.set_value(3);
+            let missing: Vec<usize> = vals.iter_missing().collect();
+            assert_eq!(missing, vec![0, 2, 4]);
+        }
     }
 }